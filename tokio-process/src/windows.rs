@@ -17,19 +17,25 @@
 
 use crate::kill::Kill;
 
+use std::ffi::OsString;
 use std::fmt;
 use std::future::Future;
 use std::io;
+use std::mem;
+use std::ops;
 use std::os::windows::prelude::*;
 use std::os::windows::process::ExitStatusExt;
 use std::pin::Pin;
 use std::process::{self, ExitStatus};
 use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::task::Context;
 use std::task::Poll;
+use std::time::Duration;
 
 use futures_util::future::Fuse;
 use futures_util::future::FutureExt;
+use futures_util::io::AsyncRead;
 
 use super::SpawnedChild;
 use mio_named_pipes::NamedPipe;
@@ -37,17 +43,23 @@ use tokio_reactor::{Handle, PollEvented};
 use tokio_sync::oneshot;
 use winapi::shared::minwindef::*;
 use winapi::shared::winerror::*;
+use winapi::um::fileapi::*;
 use winapi::um::handleapi::*;
 use winapi::um::processthreadsapi::*;
 use winapi::um::synchapi::*;
 use winapi::um::threadpoollegacyapiset::*;
+use winapi::um::wincon::*;
 use winapi::um::winbase::*;
 use winapi::um::winnt::*;
 
+// Not (yet) present in the `winapi` version this crate depends on.
+const PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE: usize = 0x0002_0016;
+
 #[must_use = "futures do nothing unless polled"]
 pub struct Child {
     child: process::Child,
     waiting: Option<Waiting>,
+    kill_on_drop: bool,
 }
 
 impl fmt::Debug for Child {
@@ -60,16 +72,47 @@ impl fmt::Debug for Child {
     }
 }
 
+impl Drop for Child {
+    fn drop(&mut self) {
+        if self.kill_on_drop {
+            // Don't bother calling TerminateProcess on a handle whose
+            // process has already exited; it would just return an error.
+            if let Ok(None) = try_wait(&self.child) {
+                let _ = self.kill();
+            }
+        }
+
+        if self.waiting.is_none() {
+            return;
+        }
+        // `self.waiting`'s own `Drop` impl (below) is about to unregister
+        // our wait and abandon the in-flight notification. Duplicate the
+        // process handle and hand it to the background reaper with a fresh
+        // registration so the child still gets reaped once it exits,
+        // instead of leaking a dangling kernel wait registration.
+        if let Ok(orphan) = duplicate_child(&self.child) {
+            reap_in_background(orphan);
+        }
+    }
+}
+
 struct Waiting {
-    rx: Fuse<oneshot::Receiver<()>>,
+    rx: Fuse<oneshot::Receiver<bool>>,
     wait_object: HANDLE,
-    tx: *mut Option<oneshot::Sender<()>>,
+    tx: *mut Option<oneshot::Sender<bool>>,
+    // The timeout this wait was registered with, so `poll_wait` can tell
+    // when a caller has asked for a different one and re-register.
+    dw_milliseconds: DWORD,
 }
 
 unsafe impl Sync for Waiting {}
 unsafe impl Send for Waiting {}
 
-pub(crate) fn spawn_child(cmd: &mut process::Command, handle: &Handle) -> io::Result<SpawnedChild> {
+pub(crate) fn spawn_child(
+    cmd: &mut process::Command,
+    handle: &Handle,
+    kill_on_drop: bool,
+) -> io::Result<SpawnedChild> {
     let mut child = cmd.spawn()?;
     let stdin = stdio(child.stdin.take(), handle)?;
     let stdout = stdio(child.stdout.take(), handle)?;
@@ -79,6 +122,7 @@ pub(crate) fn spawn_child(cmd: &mut process::Command, handle: &Handle) -> io::Re
         child: Child {
             child,
             waiting: None,
+            kill_on_drop,
         },
         stdin,
         stdout,
@@ -90,6 +134,40 @@ impl Child {
     pub fn id(&self) -> u32 {
         self.child.id()
     }
+
+    /// Waits for the child to exit, but bounds the wait to at most `duration`.
+    ///
+    /// This mirrors [`Child`]'s `Future` impl except that it resolves to
+    /// `Ok(None)` if `duration` elapses before the child exits, rather than
+    /// blocking on it indefinitely. On a timeout the child is left un-reaped
+    /// so a later call (or the `Future` impl) may continue waiting on it.
+    pub fn wait_timeout(&mut self, duration: Duration) -> WaitTimeout<'_> {
+        WaitTimeout {
+            child: self,
+            duration,
+        }
+    }
+
+    /// Waits for the child to exit, concurrently reading `stdout` and
+    /// `stderr` to EOF, and collects everything into a [`process::Output`].
+    ///
+    /// Takes `self` by value rather than `&mut self`: once the child has
+    /// exited and both pipes have hit EOF there's nothing left to wait on,
+    /// so there's no point leaving the caller with a re-pollable `Child`.
+    pub fn wait_with_output(
+        self,
+        stdout: Option<ChildStdout>,
+        stderr: Option<ChildStderr>,
+    ) -> WaitWithOutput {
+        WaitWithOutput {
+            child: self,
+            stdout,
+            stderr,
+            stdout_buf: Vec::new(),
+            stderr_buf: Vec::new(),
+            status: None,
+        }
+    }
 }
 
 impl Kill for Child {
@@ -103,44 +181,174 @@ impl Future for Child {
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let inner = Pin::get_mut(self);
-        loop {
-            if let Some(ref mut w) = inner.waiting {
-                match w.rx.poll_unpin(cx) {
-                    Poll::Ready(Ok(())) => {}
-                    Poll::Ready(Err(_)) => panic!("should not be canceled"),
-                    Poll::Pending => return Poll::Pending,
-                }
-                let status = try_wait(&inner.child)?.expect("not ready yet");
-                return Poll::Ready(Ok(status.into()));
+        // An `INFINITE` wait can never time out, so `poll_wait` only ever
+        // resolves with `Ok(Some(status))` or an error here.
+        match poll_wait(inner, cx, INFINITE) {
+            Poll::Ready(Ok(status)) => Poll::Ready(Ok(status.expect("infinite wait timed out"))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// A future returned by [`Child::wait_timeout`].
+#[must_use = "futures do nothing unless polled"]
+pub struct WaitTimeout<'a> {
+    child: &'a mut Child,
+    duration: Duration,
+}
+
+impl<'a> Future for WaitTimeout<'a> {
+    type Output = io::Result<Option<ExitStatus>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let me = Pin::get_mut(self);
+        let dw_milliseconds = duration_to_milliseconds(me.duration);
+        poll_wait(me.child, cx, dw_milliseconds)
+    }
+}
+
+/// A future returned by [`Child::wait_with_output`].
+#[must_use = "futures do nothing unless polled"]
+pub struct WaitWithOutput {
+    child: Child,
+    stdout: Option<ChildStdout>,
+    stderr: Option<ChildStderr>,
+    stdout_buf: Vec<u8>,
+    stderr_buf: Vec<u8>,
+    status: Option<ExitStatus>,
+}
+
+impl Future for WaitWithOutput {
+    type Output = io::Result<process::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let me = Pin::get_mut(self);
+
+        if me.status.is_none() {
+            match Pin::new(&mut me.child).poll(cx) {
+                Poll::Ready(Ok(status)) => me.status = Some(status),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {}
             }
+        }
+
+        if let Err(e) = drain_to_end(&mut me.stdout, &mut me.stdout_buf, cx) {
+            return Poll::Ready(Err(e));
+        }
+        if let Err(e) = drain_to_end(&mut me.stderr, &mut me.stderr_buf, cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if me.status.is_some() && me.stdout.is_none() && me.stderr.is_none() {
+            return Poll::Ready(Ok(process::Output {
+                status: me.status.take().unwrap(),
+                stdout: mem::take(&mut me.stdout_buf),
+                stderr: mem::take(&mut me.stderr_buf),
+            }));
+        }
+
+        Poll::Pending
+    }
+}
+
+/// Reads `pipe` to EOF into `buf`, clearing `pipe` to `None` once EOF is
+/// hit so later polls (once the other of stdout/stderr is still pending)
+/// are a cheap no-op instead of re-reading a closed pipe.
+fn drain_to_end(
+    pipe: &mut Option<PollEvented<NamedPipe>>,
+    buf: &mut Vec<u8>,
+    cx: &mut Context<'_>,
+) -> io::Result<()> {
+    let mut scratch = [0u8; 4096];
+    loop {
+        let io = match pipe {
+            Some(io) => io,
+            None => return Ok(()),
+        };
+        match Pin::new(io).poll_read(cx, &mut scratch) {
+            Poll::Ready(Ok(0)) => {
+                *pipe = None;
+                return Ok(());
+            }
+            Poll::Ready(Ok(n)) => buf.extend_from_slice(&scratch[..n]),
+            Poll::Ready(Err(e)) => return Err(e),
+            Poll::Pending => return Ok(()),
+        }
+    }
+}
+
+fn duration_to_milliseconds(duration: Duration) -> DWORD {
+    match duration.as_millis() {
+        // `INFINITE` is `DWORD::max_value()`, so saturate instead of
+        // accidentally requesting an infinite wait from a huge duration.
+        millis if millis >= DWORD::max_value() as u128 => DWORD::max_value() - 1,
+        millis => millis as DWORD,
+    }
+}
 
-            if let Some(e) = try_wait(&inner.child)? {
-                return Poll::Ready(Ok(e.into()));
+fn poll_wait(
+    inner: &mut Child,
+    cx: &mut Context<'_>,
+    dw_milliseconds: DWORD,
+) -> Poll<io::Result<Option<ExitStatus>>> {
+    loop {
+        if let Some(w) = &inner.waiting {
+            if w.dw_milliseconds != dw_milliseconds {
+                // The caller asked for a different timeout than the one
+                // currently registered (e.g. a shrinking deadline across
+                // repeated `wait_timeout` calls). `WT_EXECUTEONLYONCE`
+                // means the registration can't be retimed in place, so
+                // drop it (unregistering it) and fall through to register
+                // a fresh one below with the timeout actually requested.
+                inner.waiting = None;
             }
-            let (tx, rx) = oneshot::channel();
-            let ptr = Box::into_raw(Box::new(Some(tx)));
-            let mut wait_object = ptr::null_mut();
-            let rc = unsafe {
-                RegisterWaitForSingleObject(
-                    &mut wait_object,
-                    inner.child.as_raw_handle(),
-                    Some(callback),
-                    ptr as *mut _,
-                    INFINITE,
-                    WT_EXECUTEINWAITTHREAD | WT_EXECUTEONLYONCE,
-                )
+        }
+
+        if let Some(ref mut w) = inner.waiting {
+            let timed_out = match w.rx.poll_unpin(cx) {
+                Poll::Ready(Ok(timed_out)) => timed_out,
+                Poll::Ready(Err(_)) => panic!("should not be canceled"),
+                Poll::Pending => return Poll::Pending,
             };
-            if rc == 0 {
-                let err = io::Error::last_os_error();
-                drop(unsafe { Box::from_raw(ptr) });
-                return Poll::Ready(Err(err));
+            // `WT_EXECUTEONLYONCE` consumes the registration, whether it
+            // fired because the object was signaled or because the timer
+            // expired, so we must re-register on the next poll either way.
+            inner.waiting = None;
+            if timed_out {
+                return Poll::Ready(Ok(None));
             }
-            inner.waiting = Some(Waiting {
-                rx: rx.fuse(),
-                wait_object,
-                tx: ptr,
-            });
+            let status = try_wait(&inner.child)?.expect("not ready yet");
+            return Poll::Ready(Ok(Some(status.into())));
+        }
+
+        if let Some(e) = try_wait(&inner.child)? {
+            return Poll::Ready(Ok(Some(e.into())));
+        }
+        let (tx, rx) = oneshot::channel();
+        let ptr = Box::into_raw(Box::new(Some(tx)));
+        let mut wait_object = ptr::null_mut();
+        let rc = unsafe {
+            RegisterWaitForSingleObject(
+                &mut wait_object,
+                inner.child.as_raw_handle(),
+                Some(callback),
+                ptr as *mut _,
+                dw_milliseconds,
+                WT_EXECUTEINWAITTHREAD | WT_EXECUTEONLYONCE,
+            )
+        };
+        if rc == 0 {
+            let err = io::Error::last_os_error();
+            drop(unsafe { Box::from_raw(ptr) });
+            return Poll::Ready(Err(err));
         }
+        inner.waiting = Some(Waiting {
+            rx: rx.fuse(),
+            wait_object,
+            tx: ptr,
+            dw_milliseconds,
+        });
     }
 }
 
@@ -156,9 +364,511 @@ impl Drop for Waiting {
     }
 }
 
-unsafe extern "system" fn callback(ptr: PVOID, _timer_fired: BOOLEAN) {
-    let complete = &mut *(ptr as *mut Option<oneshot::Sender<()>>);
-    let _ = complete.take().unwrap().send(());
+unsafe extern "system" fn callback(ptr: PVOID, timer_fired: BOOLEAN) {
+    let complete = &mut *(ptr as *mut Option<oneshot::Sender<bool>>);
+    let _ = complete.take().unwrap().send(timer_fired != 0);
+}
+
+// Orphan reaping: keeps a dropped-but-still-running child's wait alive so
+// it still gets reaped instead of being abandoned.
+
+/// Number of children currently being reaped in the background. Exposed so
+/// callers that need a clean shutdown (tests, in particular) can poll for
+/// it to reach zero instead of guessing how long reaping takes.
+static ORPHANS_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg_attr(not(test), allow(dead_code))]
+pub(crate) fn pending_orphans() -> usize {
+    ORPHANS_PENDING.load(Ordering::SeqCst)
+}
+
+/// A process handle kept alive solely so the background reaper can observe
+/// its exit; nothing else holds on to this beyond the wait registration.
+struct Orphan {
+    child: process::Child,
+}
+
+unsafe impl Send for Orphan {}
+
+fn duplicate_child(child: &process::Child) -> io::Result<process::Child> {
+    unsafe {
+        let mut duplicate = ptr::null_mut();
+        let rc = DuplicateHandle(
+            GetCurrentProcess(),
+            child.as_raw_handle(),
+            GetCurrentProcess(),
+            &mut duplicate,
+            0,
+            FALSE,
+            DUPLICATE_SAME_ACCESS,
+        );
+        if rc == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(process::Child::from_raw_handle(duplicate as RawHandle))
+    }
+}
+
+fn reap_in_background(child: process::Child) {
+    let orphan = Box::into_raw(Box::new(Orphan { child }));
+    // Count the orphan before registering: the callback may run (and
+    // decrement) before `RegisterWaitForSingleObject` even returns.
+    ORPHANS_PENDING.fetch_add(1, Ordering::SeqCst);
+    let mut wait_object = ptr::null_mut();
+    let rc = unsafe {
+        RegisterWaitForSingleObject(
+            &mut wait_object,
+            (*orphan).child.as_raw_handle(),
+            Some(reap_callback),
+            orphan as *mut _,
+            INFINITE,
+            WT_EXECUTEINWAITTHREAD | WT_EXECUTEONLYONCE,
+        )
+    };
+    if rc == 0 {
+        // Couldn't register a wait for it; drop it here instead of leaking
+        // the box, even though that means blocking-free-ing the handle on
+        // this thread rather than in the background.
+        drop(unsafe { Box::from_raw(orphan) });
+        ORPHANS_PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+unsafe extern "system" fn reap_callback(ptr: PVOID, _timer_fired: BOOLEAN) {
+    // Registered with `INFINITE`, so this only ever fires because the
+    // process exited, not because of a timeout.
+    let orphan = Box::from_raw(ptr as *mut Orphan);
+    let _ = try_wait(&orphan.child);
+    ORPHANS_PENDING.fetch_sub(1, Ordering::SeqCst);
+    // `orphan` drops here, closing the duplicated handle. We don't call
+    // `UnregisterWaitEx`: the wait already fired, `WT_EXECUTEONLYONCE`
+    // guarantees it won't fire again, and calling it from inside our own
+    // callback risks deadlocking on the wait thread.
+}
+
+#[cfg(test)]
+mod orphan_tests {
+    use super::*;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+    use std::time::Instant;
+
+    fn noop_waker() -> Waker {
+        fn raw() -> RawWaker {
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(|_| raw(), |_| {}, |_| {}, |_| {});
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        unsafe { Waker::from_raw(raw()) }
+    }
+
+    #[test]
+    fn dropping_a_child_with_a_pending_wait_still_reaps_it() {
+        let mut cmd = process::Command::new("cmd.exe");
+        cmd.args(&["/C", "exit 0"]);
+        let mut child = Child {
+            child: cmd.spawn().expect("failed to spawn child"),
+            waiting: None,
+            kill_on_drop: false,
+        };
+
+        // Register a wait, so dropping `child` below has to hand the
+        // in-flight wait off to the background reaper instead of just
+        // reaping it inline.
+        let waker = noop_waker();
+        let _ = poll_wait(&mut child, &mut Context::from_waker(&waker), INFINITE);
+        assert!(child.waiting.is_some());
+
+        drop(child);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pending_orphans() != 0 {
+            assert!(Instant::now() < deadline, "orphaned child was never reaped");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+// Pseudo-console (ConPTY) support.
+//
+// Mirrors `spawn_child` above, but attaches the child to a Windows
+// pseudo-console instead of plain pipes. Programs that detect whether
+// they're attached to a real console (shells, editors, anything using
+// readline) behave very differently when given a ConPTY versus a bare
+// pipe, so this is the only way to drive them interactively.
+//
+// A pseudo-console is driven through a single duplex pipe: the end we keep
+// (`AsyncPtyMaster`) both sends it keyboard/input bytes and receives its
+// rendered output, exactly like a Unix PTY master fd.
+
+/// The master side of a pseudo-console, created by [`spawn_pty`].
+///
+/// Reads and writes go through the single underlying [`PollEvented`]; use
+/// [`resize`](AsyncPtyMaster::resize) to tell the console its terminal
+/// changed size.
+pub struct AsyncPtyMaster {
+    io: PollEvented<NamedPipe>,
+    pcon: HPCON,
+}
+
+unsafe impl Send for AsyncPtyMaster {}
+
+impl AsyncPtyMaster {
+    /// Notifies the pseudo-console that its terminal was resized.
+    pub fn resize(&self, rows: u16, cols: u16) -> io::Result<()> {
+        let size = COORD {
+            X: cols as i16,
+            Y: rows as i16,
+        };
+        let hr = unsafe { ResizePseudoConsole(self.pcon, size) };
+        if hr != S_OK {
+            return Err(hresult_to_io_error(hr));
+        }
+        Ok(())
+    }
+}
+
+impl ops::Deref for AsyncPtyMaster {
+    type Target = PollEvented<NamedPipe>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.io
+    }
+}
+
+impl ops::DerefMut for AsyncPtyMaster {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.io
+    }
+}
+
+impl Drop for AsyncPtyMaster {
+    fn drop(&mut self) {
+        // Blocks until the console and anything still reading or writing
+        // to it have drained, per `ClosePseudoConsole`'s documented
+        // behavior.
+        unsafe {
+            ClosePseudoConsole(self.pcon);
+        }
+    }
+}
+
+fn hresult_to_io_error(hr: i32) -> io::Error {
+    // `HRESULT_CODE`: the low 16 bits carry the underlying Win32 error.
+    io::Error::from_raw_os_error(hr & 0xFFFF)
+}
+
+/// Creates a duplex, overlapped-capable named pipe, returning our (server)
+/// end and a plain client handle suitable for handing to the console.
+fn pty_pipe() -> io::Result<(NamedPipe, HANDLE)> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let name = format!(
+        r"\\.\pipe\tokio-process-conpty-{}-{}",
+        process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+    let server = NamedPipe::new(&name)?;
+    let mut wide_name: Vec<u16> = name.encode_utf16().chain(Some(0)).collect();
+    let client = unsafe {
+        CreateFileW(
+            wide_name.as_mut_ptr(),
+            GENERIC_READ | GENERIC_WRITE,
+            0,
+            ptr::null_mut(),
+            OPEN_EXISTING,
+            FILE_FLAG_OVERLAPPED,
+            ptr::null_mut(),
+        )
+    };
+    if client == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((server, client))
+}
+
+/// Builds a `PROC_THREAD_ATTRIBUTE_LIST` that attaches `pcon` to a child
+/// via `PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE`, for use in a
+/// `STARTUPINFOEXW`.
+struct ProcThreadAttributeList {
+    buffer: Vec<u8>,
+    // `UpdateProcThreadAttribute` is documented to read `*lpValue` again
+    // whenever the attribute list is used (e.g. from `CreateProcessW`), so
+    // the `HPCON` it points at must live as long as `buffer` does, not just
+    // for the duration of `new()`.
+    pcon: Box<HPCON>,
+}
+
+impl ProcThreadAttributeList {
+    fn new(pcon: HPCON) -> io::Result<Self> {
+        let mut size = 0;
+        unsafe {
+            InitializeProcThreadAttributeList(ptr::null_mut(), 1, 0, &mut size);
+        }
+        let mut buffer = vec![0u8; size];
+        let list = buffer.as_mut_ptr() as *mut PROC_THREAD_ATTRIBUTE_LIST;
+        if unsafe { InitializeProcThreadAttributeList(list, 1, 0, &mut size) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pcon = Box::new(pcon);
+        let rc = unsafe {
+            UpdateProcThreadAttribute(
+                list,
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE,
+                pcon.as_mut() as *mut HPCON as *mut _,
+                mem::size_of::<HPCON>(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        if rc == 0 {
+            let err = io::Error::last_os_error();
+            unsafe { DeleteProcThreadAttributeList(list) };
+            return Err(err);
+        }
+        Ok(ProcThreadAttributeList { buffer, pcon })
+    }
+
+    fn as_ptr(&mut self) -> *mut PROC_THREAD_ATTRIBUTE_LIST {
+        self.buffer.as_mut_ptr() as *mut _
+    }
+}
+
+impl Drop for ProcThreadAttributeList {
+    fn drop(&mut self) {
+        unsafe { DeleteProcThreadAttributeList(self.as_ptr()) };
+    }
+}
+
+/// Builds a Win32 command line from `cmd`'s program and arguments, needed
+/// here since `CreateProcessW` (unlike `Command::spawn`) takes a single
+/// command-line string rather than a program/argv pair.
+fn command_line(cmd: &process::Command) -> Vec<u16> {
+    let mut line = Vec::new();
+    append_arg(&mut line, cmd.get_program());
+    for arg in cmd.get_args() {
+        line.push(b' ' as u16);
+        append_arg(&mut line, arg);
+    }
+    line.push(0);
+    line
+}
+
+/// Quotes and appends `arg` to `line`, following the same
+/// backslash-run-then-quote algorithm `std::process::Command` uses
+/// internally when building a `CreateProcessW` command line: a run of `n`
+/// backslashes immediately before a literal `"` becomes `2n + 1`
+/// backslashes (the last one escaping the quote), and a run before the
+/// closing quote becomes `2n`, since the MSVC runtime's argv parser
+/// treats backslashes specially only when they precede a `"`.
+fn append_arg(line: &mut Vec<u16>, arg: &std::ffi::OsStr) {
+    let arg: Vec<u16> = arg.encode_wide().collect();
+    let quote = arg.is_empty() || arg.iter().any(|&c| c == b' ' as u16 || c == b'\t' as u16);
+    if quote {
+        line.push(b'"' as u16);
+    }
+
+    let mut backslashes: usize = 0;
+    for &c in &arg {
+        if c == b'\\' as u16 {
+            backslashes += 1;
+        } else {
+            if c == b'"' as u16 {
+                line.extend((0..=backslashes).map(|_| b'\\' as u16));
+            }
+            backslashes = 0;
+        }
+        line.push(c);
+    }
+
+    if quote {
+        line.extend((0..backslashes).map(|_| b'\\' as u16));
+        line.push(b'"' as u16);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(arg: &str) -> String {
+        let mut line = Vec::new();
+        append_arg(&mut line, std::ffi::OsStr::new(arg));
+        String::from_utf16(&line).unwrap()
+    }
+
+    #[test]
+    fn plain_args_are_left_unquoted() {
+        assert_eq!(quote("plain"), "plain");
+    }
+
+    #[test]
+    fn trailing_backslashes_are_doubled_before_the_closing_quote() {
+        assert_eq!(quote(r"C:\Program Files\"), r#""C:\Program Files\\""#);
+    }
+
+    #[test]
+    fn embedded_quotes_are_escaped() {
+        assert_eq!(quote(r#"say "hi""#), r#""say \"hi\"""#);
+    }
+}
+
+/// Spawns `cmd` attached to a new pseudo-console of size `rows` by `cols`,
+/// returning the console's master end and a [`Child`] that completes
+/// exactly as one returned from [`spawn_child`].
+pub(crate) fn spawn_pty(
+    cmd: &mut process::Command,
+    handle: &Handle,
+    rows: u16,
+    cols: u16,
+) -> io::Result<(AsyncPtyMaster, Child)> {
+    let (master_pipe, console_end) = pty_pipe()?;
+
+    let size = COORD {
+        X: cols as i16,
+        Y: rows as i16,
+    };
+    let mut pcon = ptr::null_mut();
+    let hr = unsafe { CreatePseudoConsole(size, console_end, console_end, 0, &mut pcon) };
+    // Once `CreatePseudoConsole` returns, the console owns these handles;
+    // our copies are no longer needed, whether it succeeded or not.
+    unsafe {
+        CloseHandle(console_end);
+    }
+    if hr != S_OK {
+        return Err(hresult_to_io_error(hr));
+    }
+
+    let result = spawn_attached_to_pty(cmd, pcon);
+    let (process_info, attr_list) = match result {
+        Ok(ok) => ok,
+        Err(err) => {
+            unsafe { ClosePseudoConsole(pcon) };
+            return Err(err);
+        }
+    };
+    drop(attr_list);
+    unsafe {
+        CloseHandle(process_info.hThread);
+    }
+
+    let child = unsafe { process::Child::from_raw_handle(process_info.hProcess as RawHandle) };
+    let io = PollEvented::new_with_handle(master_pipe, handle)?;
+
+    Ok((
+        AsyncPtyMaster { io, pcon },
+        Child {
+            child,
+            waiting: None,
+            kill_on_drop: false,
+        },
+    ))
+}
+
+fn spawn_attached_to_pty(
+    cmd: &mut process::Command,
+    pcon: HPCON,
+) -> io::Result<(PROCESS_INFORMATION, ProcThreadAttributeList)> {
+    let mut attr_list = ProcThreadAttributeList::new(pcon)?;
+    let mut startup_info: STARTUPINFOEXW = unsafe { mem::zeroed() };
+    startup_info.StartupInfo.cb = mem::size_of::<STARTUPINFOEXW>() as DWORD;
+    startup_info.lpAttributeList = attr_list.as_ptr();
+
+    let mut process_info: PROCESS_INFORMATION = unsafe { mem::zeroed() };
+    let mut cmdline = command_line(cmd);
+    let mut env_block = environment_block(cmd);
+    let mut cwd = current_dir(cmd);
+    let rc = unsafe {
+        CreateProcessW(
+            ptr::null_mut(),
+            cmdline.as_mut_ptr(),
+            ptr::null_mut(),
+            ptr::null_mut(),
+            FALSE,
+            EXTENDED_STARTUPINFO_PRESENT | CREATE_UNICODE_ENVIRONMENT,
+            env_block
+                .as_mut()
+                .map_or(ptr::null_mut(), |block| block.as_mut_ptr() as *mut _),
+            cwd.as_mut()
+                .map_or(ptr::null_mut(), |cwd| cwd.as_mut_ptr()),
+            &mut startup_info.StartupInfo,
+            &mut process_info,
+        )
+    };
+    if rc == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((process_info, attr_list))
+}
+
+/// An environment variable name, ordered case-insensitively (as Windows
+/// treats them) while preserving the original casing for output. Mirrors
+/// `std`'s internal `EnvKey`.
+#[derive(Eq)]
+struct EnvKey(OsString);
+
+impl EnvKey {
+    fn folded(&self) -> String {
+        self.0.to_string_lossy().to_uppercase()
+    }
+}
+
+impl PartialEq for EnvKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.folded() == other.folded()
+    }
+}
+
+impl PartialOrd for EnvKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for EnvKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.folded().cmp(&other.folded())
+    }
+}
+
+/// Builds a `CreateProcessW`-style environment block (`KEY=VALUE\0` pairs
+/// terminated by an empty string) from the parent's environment with
+/// `cmd`'s `.env()`/`.env_remove()` overrides applied, mirroring what
+/// `Command::spawn` does internally. Returns `None` when `cmd` has no
+/// overrides at all, so the caller can pass a null `lpEnvironment` and let
+/// the child inherit the parent's environment directly.
+fn environment_block(cmd: &process::Command) -> Option<Vec<u16>> {
+    let mut has_overrides = false;
+    let mut env: std::collections::BTreeMap<EnvKey, OsString> = std::env::vars_os()
+        .map(|(key, value)| (EnvKey(key), value))
+        .collect();
+    for (key, value) in cmd.get_envs() {
+        has_overrides = true;
+        match value {
+            Some(value) => {
+                env.insert(EnvKey(key.to_os_string()), value.to_os_string());
+            }
+            None => {
+                env.remove(&EnvKey(key.to_os_string()));
+            }
+        }
+    }
+    if !has_overrides {
+        return None;
+    }
+
+    let mut block = Vec::new();
+    for (key, value) in env {
+        block.extend(key.0.encode_wide());
+        block.push(b'=' as u16);
+        block.extend(value.encode_wide());
+        block.push(0);
+    }
+    block.push(0);
+    Some(block)
+}
+
+fn current_dir(cmd: &process::Command) -> Option<Vec<u16>> {
+    let dir = cmd.get_current_dir()?;
+    Some(dir.as_os_str().encode_wide().chain(Some(0)).collect())
 }
 
 pub fn try_wait(child: &process::Child) -> io::Result<Option<ExitStatus>> {